@@ -0,0 +1,238 @@
+//! Fixed-point math for USD values, health factors and liquidation amounts.
+//!
+//! `Decimal` is a WAD (1e18) scaled fixed-point number backed by a 192-bit integer, so
+//! intermediate products like `amount * price` never overflow. Every operation is checked and
+//! returns `ErrorCode::MathOverflow` instead of panicking. Multiplication and division round
+//! down by default, the `_ceil` variants round up; callers pick whichever favors the protocol.
+
+use anchor_lang::prelude::*;
+
+use crate::ErrorCode;
+
+#[allow(clippy::assign_op_pattern, clippy::manual_div_ceil, clippy::ptr_offset_with_cast, clippy::manual_range_contains)]
+mod uint_types {
+    use uint::construct_uint;
+
+    construct_uint! {
+        pub struct U192(3);
+    }
+}
+
+pub use uint_types::U192;
+
+pub const WAD: u64 = 1_000_000_000_000_000_000; // 1e18
+pub const RAY: u128 = 1_000_000_000_000_000_000_000_000_000; // 1e27
+const WAD_TO_RAY: u64 = 1_000_000_000; // 1e9
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Decimal(pub U192);
+
+impl Decimal {
+    pub fn zero() -> Self {
+        Self(U192::zero())
+    }
+
+    pub fn one() -> Self {
+        Self(U192::from(WAD))
+    }
+
+    /// Whole number, e.g. a token amount in base units
+    pub fn from_integer(value: u64) -> Self {
+        Self(U192::from(value) * U192::from(WAD))
+    }
+
+    /// Value already scaled by WAD, e.g. a 1e18 price or ratio
+    pub fn from_wad(wad: u128) -> Self {
+        Self(U192::from(wad))
+    }
+
+    /// Value scaled by RAY (1e27), rounded down to WAD precision
+    pub fn from_ray(ray: u128) -> Self {
+        Self(U192::from(ray) / U192::from(WAD_TO_RAY))
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.0.is_zero()
+    }
+
+    pub fn try_to_wad(&self) -> Result<u128> {
+        to_u128(self.0)
+    }
+
+    pub fn try_to_ray(&self) -> Result<u128> {
+        let ray = self.0.checked_mul(U192::from(WAD_TO_RAY)).ok_or(ErrorCode::MathOverflow)?;
+        to_u128(ray)
+    }
+
+    pub fn try_floor_u64(&self) -> Result<u64> {
+        to_u64(self.0 / U192::from(WAD))
+    }
+
+    pub fn try_ceil_u64(&self) -> Result<u64> {
+        let wad = U192::from(WAD);
+        let ceil = self.0.checked_add(wad - 1).ok_or(ErrorCode::MathOverflow)? / wad;
+        to_u64(ceil)
+    }
+
+    pub fn try_add(self, rhs: Self) -> Result<Self> {
+        Ok(Self(self.0.checked_add(rhs.0).ok_or(ErrorCode::MathOverflow)?))
+    }
+
+    pub fn try_sub(self, rhs: Self) -> Result<Self> {
+        Ok(Self(self.0.checked_sub(rhs.0).ok_or(ErrorCode::MathOverflow)?))
+    }
+
+    pub fn try_mul(self, rhs: Self) -> Result<Self> {
+        let product = self.0.checked_mul(rhs.0).ok_or(ErrorCode::MathOverflow)?;
+        Ok(Self(product / U192::from(WAD)))
+    }
+
+    pub fn try_mul_ceil(self, rhs: Self) -> Result<Self> {
+        let product = self.0.checked_mul(rhs.0).ok_or(ErrorCode::MathOverflow)?;
+        Ok(Self(div_ceil(product, U192::from(WAD))?))
+    }
+
+    pub fn try_div(self, rhs: Self) -> Result<Self> {
+        require!(!rhs.is_zero(), ErrorCode::MathOverflow);
+        let scaled = self.0.checked_mul(U192::from(WAD)).ok_or(ErrorCode::MathOverflow)?;
+        Ok(Self(scaled / rhs.0))
+    }
+
+    pub fn try_div_ceil(self, rhs: Self) -> Result<Self> {
+        require!(!rhs.is_zero(), ErrorCode::MathOverflow);
+        let scaled = self.0.checked_mul(U192::from(WAD)).ok_or(ErrorCode::MathOverflow)?;
+        Ok(Self(div_ceil(scaled, rhs.0)?))
+    }
+}
+
+fn div_ceil(numerator: U192, denominator: U192) -> Result<U192> {
+    let quotient = numerator / denominator;
+    if quotient * denominator == numerator {
+        Ok(quotient)
+    } else {
+        quotient.checked_add(U192::one()).ok_or(ErrorCode::MathOverflow.into())
+    }
+}
+
+fn to_u128(value: U192) -> Result<u128> {
+    require!(value.bits() <= 128, ErrorCode::MathOverflow);
+    Ok(value.as_u128())
+}
+
+fn to_u64(value: U192) -> Result<u64> {
+    require!(value.bits() <= 64, ErrorCode::MathOverflow);
+    Ok(value.as_u64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_bigint::BigUint;
+    use proptest::prelude::*;
+
+    fn big(value: u128) -> BigUint {
+        BigUint::from(value)
+    }
+
+    fn big_wad() -> BigUint {
+        big(WAD as u128)
+    }
+
+    fn big_div_ceil(numerator: BigUint, denominator: &BigUint) -> BigUint {
+        (numerator + denominator - 1u32) / denominator
+    }
+
+    fn to_big(decimal: Decimal) -> BigUint {
+        let mut bytes = [0u8; 24];
+        decimal.0.to_little_endian(&mut bytes);
+        BigUint::from_bytes_le(&bytes)
+    }
+
+    /// Results that do not fit back into 192 bits must be reported as overflow
+    fn fits_u192(value: &BigUint) -> bool {
+        value.bits() <= 192
+    }
+
+    #[test]
+    fn converts_between_scales() {
+        assert_eq!(Decimal::from_integer(3).try_to_wad().unwrap(), 3 * WAD as u128);
+        assert_eq!(Decimal::from_ray(RAY).try_to_wad().unwrap(), WAD as u128);
+        assert_eq!(Decimal::one().try_to_ray().unwrap(), RAY);
+        assert_eq!(Decimal::from_wad(1_500_000_000_000_000_000).try_floor_u64().unwrap(), 1);
+        assert_eq!(Decimal::from_wad(1_500_000_000_000_000_000).try_ceil_u64().unwrap(), 2);
+        assert_eq!(Decimal::from_integer(2).try_ceil_u64().unwrap(), 2);
+    }
+
+    #[test]
+    fn reports_overflow_instead_of_panicking() {
+        let max = Decimal(U192::MAX);
+        assert!(max.try_add(Decimal::from_wad(1)).is_err());
+        assert!(Decimal::zero().try_sub(Decimal::from_wad(1)).is_err());
+        assert!(max.try_mul(Decimal::from_integer(2)).is_err());
+        assert!(Decimal::one().try_div(Decimal::zero()).is_err());
+        assert!(Decimal::one().try_div_ceil(Decimal::zero()).is_err());
+        assert!(Decimal::from_integer(u64::MAX).try_mul(Decimal::from_integer(2)).unwrap().try_floor_u64().is_err());
+        assert!(max.try_to_wad().is_err());
+    }
+
+    proptest! {
+        #[test]
+        fn mul_matches_reference(a in any::<u128>(), b in any::<u128>()) {
+            let (x, y) = (Decimal::from_wad(a), Decimal::from_wad(b));
+            let product = big(a) * big(b);
+
+            let floor = &product / big_wad();
+            let ceil = big_div_ceil(product.clone(), &big_wad());
+            if fits_u192(&product) {
+                prop_assert_eq!(to_big(x.try_mul(y).unwrap()), floor);
+                prop_assert_eq!(to_big(x.try_mul_ceil(y).unwrap()), ceil);
+            } else {
+                prop_assert!(x.try_mul(y).is_err());
+                prop_assert!(x.try_mul_ceil(y).is_err());
+            }
+        }
+
+        #[test]
+        fn div_matches_reference(a in any::<u128>(), b in 1..=u128::MAX) {
+            let (x, y) = (Decimal::from_wad(a), Decimal::from_wad(b));
+            let scaled = big(a) * big_wad();
+
+            prop_assert_eq!(to_big(x.try_div(y).unwrap()), &scaled / big(b));
+            prop_assert_eq!(to_big(x.try_div_ceil(y).unwrap()), big_div_ceil(scaled, &big(b)));
+        }
+
+        #[test]
+        fn add_sub_match_reference(a in any::<u128>(), b in any::<u128>()) {
+            let (x, y) = (Decimal::from_wad(a), Decimal::from_wad(b));
+            prop_assert_eq!(to_big(x.try_add(y).unwrap()), big(a) + big(b));
+            if a >= b {
+                prop_assert_eq!(to_big(x.try_sub(y).unwrap()), big(a) - big(b));
+            } else {
+                prop_assert!(x.try_sub(y).is_err());
+            }
+        }
+
+        #[test]
+        fn rounding_brackets_exact_value(a in any::<u128>(), b in 1..=u128::MAX) {
+            let (x, y) = (Decimal::from_wad(a), Decimal::from_wad(b));
+            let (floor, ceil) = (x.try_div(y).unwrap(), x.try_div_ceil(y).unwrap());
+            prop_assert!(floor <= ceil);
+            prop_assert!(to_big(ceil) - to_big(floor) <= BigUint::from(1u32));
+        }
+
+        #[test]
+        fn integer_conversions_match_reference(a in any::<u128>()) {
+            let x = Decimal::from_wad(a);
+            let floor = big(a) / big_wad();
+            let ceil = big_div_ceil(big(a), &big_wad());
+            match x.try_floor_u64() {
+                Ok(value) => prop_assert_eq!(big(value as u128), floor),
+                Err(_) => prop_assert!(floor > big(u64::MAX as u128)),
+            }
+            match x.try_ceil_u64() {
+                Ok(value) => prop_assert_eq!(big(value as u128), ceil),
+                Err(_) => prop_assert!(ceil > big(u64::MAX as u128)),
+            }
+        }
+    }
+}